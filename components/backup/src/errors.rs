@@ -1,17 +1,22 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::io::Error as IoError;
+use std::time::Duration;
 use std::{error, result};
 
 use kvproto::backup::Error as ErrorPb;
 use kvproto::errorpb::{Error as RegionError, ServerIsBusy};
-use kvproto::kvrpcpb::{KeyError, LockInfo};
+use kvproto::kvrpcpb::{Deadlock, KeyError, LockInfo, WriteConflict};
 use tikv::storage::kv::Error as EngineError;
 use tikv::storage::mvcc::Error as MvccError;
 use tikv::storage::txn::Error as TxnError;
 
 use crate::metrics::*;
 
+/// Suggested backoff before a client retries a transient region error, e.g. after a
+/// leader transfer or while a store is catching up on apply.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
 impl Into<ErrorPb> for Error {
     // TODO: test error conversion.
     fn into(self) -> ErrorPb {
@@ -47,6 +52,8 @@ impl Into<ErrorPb> for Error {
                     BACKUP_RANGE_ERROR_VEC
                         .with_label_values(&["server_is_busy"])
                         .inc();
+                    e.mut_server_is_busy()
+                        .set_backoff_ms(RETRY_BACKOFF.as_millis() as u64);
                 } else if e.has_stale_command() {
                     BACKUP_RANGE_ERROR_VEC
                         .with_label_values(&["stale_command"])
@@ -59,6 +66,15 @@ impl Into<ErrorPb> for Error {
 
                 err.set_region_error(e);
             }
+            // BLOCKED(universonic/tikv#chunk0-1): unimplemented. The request asks for
+            // `use_async_commit`/`min_commit_ts`/`secondaries` to be threaded into `LockInfo`,
+            // but that requires widening `tikv::storage::mvcc::Error::KeyIsLocked` (and the
+            // `Lock` it is built from), which lives in the `tikv` crate proper and is not part
+            // of this source tree/checkout — there is nothing here to change on the producer
+            // side. This arm is therefore still exactly the pre-request behavior below; do not
+            // read it as done. Needs the upstream MVCC-side change landed first, then: widen
+            // this destructuring and call `info.set_use_async_commit(..)`,
+            // `info.set_min_commit_ts(..)`, `info.set_secondaries(..)`.
             Error::Txn(TxnError::Mvcc(MvccError::KeyIsLocked {
                 primary,
                 ts,
@@ -79,11 +95,74 @@ impl Into<ErrorPb> for Error {
                 e.set_locked(info);
                 err.set_kv_error(e);
             }
+            Error::Txn(TxnError::Mvcc(MvccError::WriteConflict {
+                start_ts,
+                conflict_start_ts,
+                conflict_commit_ts,
+                key,
+                primary,
+            })) => {
+                BACKUP_RANGE_ERROR_VEC
+                    .with_label_values(&["write_conflict"])
+                    .inc();
+                let mut conflict = WriteConflict::new();
+                conflict.set_start_ts(start_ts);
+                conflict.set_conflict_ts(conflict_start_ts);
+                conflict.set_conflict_commit_ts(conflict_commit_ts);
+                conflict.set_key(key);
+                conflict.set_primary(primary);
+                let mut e = KeyError::new();
+                e.set_conflict(conflict);
+                err.set_kv_error(e);
+            }
+            e @ Error::Txn(TxnError::Mvcc(MvccError::TxnLockNotFound { .. })) => {
+                BACKUP_RANGE_ERROR_VEC
+                    .with_label_values(&["txn_lock_not_found"])
+                    .inc();
+                let mut key_err = KeyError::new();
+                key_err.set_retryable(format!("{}", e));
+                err.set_kv_error(key_err);
+            }
+            // A key that is reported `Committed` already finished; the transaction cannot be
+            // resolved differently on a retry, so this must not go through `retryable` (that
+            // would just send the client into a retry loop against a fait accompli). Surface
+            // it as `abort` instead, matching how other terminal, non-retryable conditions are
+            // reported through `KeyError`.
+            e @ Error::Txn(TxnError::Mvcc(MvccError::Committed { .. })) => {
+                BACKUP_RANGE_ERROR_VEC.with_label_values(&["committed"]).inc();
+                let mut key_err = KeyError::new();
+                key_err.set_abort(format!("{}", e));
+                err.set_kv_error(key_err);
+            }
+            e @ Error::Txn(TxnError::Mvcc(MvccError::PessimisticLockNotFound { .. })) => {
+                BACKUP_RANGE_ERROR_VEC
+                    .with_label_values(&["pessimistic_lock_not_found"])
+                    .inc();
+                let mut key_err = KeyError::new();
+                key_err.set_retryable(format!("{}", e));
+                err.set_kv_error(key_err);
+            }
+            Error::Txn(TxnError::Mvcc(MvccError::Deadlock {
+                lock_ts,
+                lock_key,
+                deadlock_key_hash,
+                ..
+            })) => {
+                BACKUP_RANGE_ERROR_VEC.with_label_values(&["deadlock"]).inc();
+                let mut deadlock = Deadlock::new();
+                deadlock.set_lock_ts(lock_ts);
+                deadlock.set_lock_key(lock_key);
+                deadlock.set_deadlock_key_hash(deadlock_key_hash);
+                let mut key_err = KeyError::new();
+                key_err.set_deadlock(deadlock);
+                err.set_kv_error(key_err);
+            }
             timeout @ Error::Engine(EngineError::Timeout(_)) => {
                 BACKUP_RANGE_ERROR_VEC.with_label_values(&["timeout"]).inc();
                 let mut busy = ServerIsBusy::default();
                 let reason = format!("{}", timeout);
                 busy.set_reason(reason.clone());
+                busy.set_backoff_ms(RETRY_BACKOFF.as_millis() as u64);
                 let mut e = RegionError::default();
                 e.set_message(reason);
                 e.set_server_is_busy(busy);
@@ -115,6 +194,40 @@ pub enum Error {
     ClusterID { current: u64, request: u64 },
 }
 
+impl Error {
+    /// Returns whether this error is transient and worth retrying, e.g. a leader transfer
+    /// or a busy store, as opposed to a fatal error like a cluster ID mismatch.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Engine(EngineError::Timeout(_)) => true,
+            Error::Engine(EngineError::Request(e))
+            | Error::Txn(TxnError::Engine(EngineError::Request(e)))
+            | Error::Txn(TxnError::Mvcc(MvccError::Engine(EngineError::Request(e)))) => {
+                e.has_not_leader()
+                    || e.has_region_not_found()
+                    || e.has_epoch_not_match()
+                    || e.has_server_is_busy()
+                    || e.has_stale_command()
+            }
+            _ => false,
+        }
+    }
+
+    /// Suggests how long the caller should back off before retrying, if at all.
+    ///
+    /// The same value is surfaced on the wire as `ServerIsBusy.backoff_ms` on the
+    /// `server_is_busy` region-error and engine-timeout paths in `Into<ErrorPb>`; this method
+    /// is the in-process equivalent for callers that still hold the `Error` and haven't
+    /// converted it yet.
+    pub fn retry_delay(&self) -> Option<Duration> {
+        if self.is_retryable() {
+            Some(RETRY_BACKOFF)
+        } else {
+            None
+        }
+    }
+}
+
 macro_rules! impl_from {
     ($($inner:ty => $container:ident,)+) => {
         $(
@@ -135,4 +248,47 @@ impl_from! {
     TxnError => Txn,
 }
 
-pub type Result<T> = result::Result<T, Error>;
\ No newline at end of file
+pub type Result<T> = result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region_error_of(set: impl FnOnce(&mut RegionError)) -> Error {
+        let mut e = RegionError::default();
+        set(&mut e);
+        Error::Engine(EngineError::Request(e))
+    }
+
+    #[test]
+    fn transient_region_errors_are_retryable() {
+        assert!(region_error_of(|e| e.set_not_leader(Default::default())).is_retryable());
+        assert!(region_error_of(|e| e.set_region_not_found(Default::default())).is_retryable());
+        assert!(region_error_of(|e| e.set_epoch_not_match(Default::default())).is_retryable());
+        assert!(region_error_of(|e| e.set_server_is_busy(Default::default())).is_retryable());
+        assert!(region_error_of(|e| e.set_stale_command(Default::default())).is_retryable());
+        assert!(Error::Engine(EngineError::Timeout(Duration::from_secs(1))).is_retryable());
+    }
+
+    #[test]
+    fn fatal_errors_are_not_retryable() {
+        assert!(!region_error_of(|e| e.set_store_not_match(Default::default())).is_retryable());
+        assert!(!Error::ClusterID {
+            current: 1,
+            request: 2,
+        }
+        .is_retryable());
+    }
+
+    #[test]
+    fn retry_delay_is_only_set_when_retryable() {
+        let retryable = region_error_of(|e| e.set_not_leader(Default::default()));
+        assert_eq!(retryable.retry_delay(), Some(RETRY_BACKOFF));
+
+        let fatal = Error::ClusterID {
+            current: 1,
+            request: 2,
+        };
+        assert_eq!(fatal.retry_delay(), None);
+    }
+}
\ No newline at end of file